@@ -1,34 +1,327 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 
-use getopts::Options;
-use glob::glob;
+use getopts::{Matches, Options};
+use glob::{glob, Pattern};
 
 #[derive(Debug)]
 struct Opts {
-    name: String,
     reverse: bool,
-    percentage: bool,
-    path: bool,
+    columns: Vec<Column>,
+    percent_base: PercentBase,
+    format: Format,
+    auto_strip: bool,
+    summary_format: Option<String>,
+    path_display: PathDisplay,
+    sort: Vec<(SortKey, Direction)>,
+    none_first: bool,
+    ignore_case: bool,
     min: f64,
-    input: String,
+    min_count: u64,
+    prune_under: Option<u64>,
+    lump_below: Option<f64>,
+    max_entries: Option<usize>,
+    sample: Option<usize>,
+    seed: Option<u64>,
+    ext_summary: Option<usize>,
+    age_ranges: Option<Vec<(String, Duration)>>,
+    rollup: Option<Rollup>,
+    checkpoint: Option<String>,
+    resume: Option<String>,
+    append_log: Option<String>,
+    quiet: bool,
+    exit_zero_on_empty: bool,
+    profile: bool,
+    dustignore: bool,
+    deterministic: bool,
+    no_sort: bool,
+    dereference: bool,
+    dedup_targets: bool,
+    no_symlink_loops: bool,
+    follow_limit: Option<usize>,
+    count_special: bool,
+    exclude_pseudo: bool,
+    files_only_size: bool,
+    entry_limit: Option<usize>,
+    io_retries: usize,
+    fail_fast: bool,
+    dedup_paths: bool,
+    children: bool,
+    glob_root: Option<PathBuf>,
+    inputs: Vec<String>,
 }
 
 impl Opts {
     pub fn parse() -> Self {
-        let (name, args) = {
-            let mut args = env::args();
-            (args.next().unwrap(), args)
-        };
+        let mut args = env::args();
+        let name = args.next().unwrap();
+        Self::parse_from(&name, args)
+    }
 
+    fn parse_from<I: Iterator<Item = String>>(name: &str, args: I) -> Self {
         let mut opts = Options::new();
         opts.optflag("h", "help", "shows this help message");
         opts.optflag("r", "reverse", "reverse ordering");
         opts.optflag("P", "percentages", "show percentages");
         opts.optflag("p", "path", "sort by path, instead of by size");
+        opts.optopt(
+            "",
+            "sort",
+            "sort by a comma separated list of keys: size, path, count, ext, mtime, random. \
+             each can be suffixed with :asc or :desc",
+            "KEYS",
+        );
+        opts.optflag("f", "ignore-case", "sort paths case-insensitively");
+        opts.optflag("", "none-first", "sort entries without an mtime first");
+        opts.optflag(
+            "",
+            "none-last",
+            "sort entries without an mtime last (default)",
+        );
         opts.optopt("m", "min", "show only minimum percentage", "FLOAT");
+        opts.optopt(
+            "",
+            "lump-below",
+            "collapse entries under this percentage into a single <other> row",
+            "FLOAT",
+        );
+        opts.optopt(
+            "",
+            "prune-under",
+            "hide directories smaller than SIZE (like 10M) after the walk, totals still count them",
+            "SIZE",
+        );
+        opts.optopt(
+            "",
+            "min-count",
+            "hide entries with fewer than N files, totals still count them",
+            "N",
+        );
+        opts.optflag("", "bytes", "show sizes in bytes");
+        opts.optflag("", "human", "show human readable sizes, even with --raw");
+        opts.optopt(
+            "",
+            "unit",
+            "show every size in one unit, even with --raw",
+            "B|K|M|G|T|P|E",
+        );
+        opts.optflag("", "no-separator", "don't group the digits of counts");
+        opts.optflag(
+            "",
+            "raw",
+            "machine readable output: bytes, plain counts, tab separated, no totals",
+        );
+        opts.optflag(
+            "",
+            "compact",
+            "separate the columns with a single space instead of aligning them",
+        );
+        opts.optflag(
+            "",
+            "relative-time",
+            "show the mtime column as how long ago, like 5d, in the units of --age-ranges",
+        );
+        opts.optopt(
+            "",
+            "percent-precision",
+            "decimal places for the percent column (default 2)",
+            "N",
+        );
+        opts.optflag(
+            "",
+            "print0-records",
+            "end each line with a NUL instead of a newline",
+        );
+        opts.optmulti(
+            "",
+            "output-encoding",
+            "line endings and BOM of the output: lf (default), crlf, utf8-bom",
+            "ENC",
+        );
+        opts.optopt(
+            "",
+            "path-display",
+            "show the final component, the path as given (default) or the absolute path",
+            "name|relative|absolute",
+        );
+        opts.optopt(
+            "",
+            "summary-format",
+            "replace the totals footer with TEMPLATE, using {total_size}, {total_bytes}, \
+             {total_count} and {entry_count}",
+            "TEMPLATE",
+        );
+        opts.optopt(
+            "",
+            "format-file",
+            "read the summary template from FILE, skipping lines that start with #",
+            "FILE",
+        );
+        opts.optflag(
+            "",
+            "auto-strip",
+            "show paths relative to the common directory of all entries, except with --raw",
+        );
+        opts.optopt(
+            "",
+            "percent-base",
+            "percentages of the whole scan (default) or of the displayed entries",
+            "scan|displayed",
+        );
+        opts.optopt(
+            "",
+            "columns",
+            "comma separated list of columns to show: size, percent, count, mtime, path",
+            "LIST",
+        );
+        opts.optopt(
+            "",
+            "max-entries",
+            "stop collecting entries after N, totals still cover everything",
+            "N",
+        );
+        opts.optopt(
+            "",
+            "sample",
+            "show N entries picked at random, totals still cover everything",
+            "N",
+        );
+        opts.optopt("", "seed", "seed for --sort random and --sample", "N");
+        opts.optflag(
+            "c",
+            "children",
+            "given a single directory, list each of its children instead",
+        );
+        opts.optopt(
+            "",
+            "glob-root",
+            "resolve relative patterns against DIR and show paths relative to it",
+            "DIR",
+        );
+        opts.optflag(
+            "",
+            "deduplicate-paths",
+            "skip paths that repeat or are inside another given path",
+        );
+        opts.optflagopt(
+            "",
+            "ext-summary",
+            "list the N extensions using the most space after the totals (default 10)",
+            "N",
+        );
+        opts.optflag(
+            "",
+            "age-buckets",
+            "show how much data was last modified within each age range",
+        );
+        opts.optopt(
+            "",
+            "age-ranges",
+            "comma separated ages for --age-buckets, in s, min, h, d, w, mo or y (default 1w,1mo,1y)",
+            "LIST",
+        );
+        opts.optflag(
+            "",
+            "by-day",
+            "show how much file data was last modified each day",
+        );
+        opts.optflag("", "by-week", "like --by-day, for weeks starting on monday");
+        opts.optflag("", "by-month", "like --by-day, for months");
+        opts.optopt(
+            "",
+            "checkpoint",
+            "record each fully scanned path in FILE",
+            "FILE",
+        );
+        opts.optopt(
+            "",
+            "resume",
+            "reuse the paths recorded in a --checkpoint FILE and keep recording to it",
+            "FILE",
+        );
+        opts.optopt(
+            "",
+            "append-log",
+            "append the time, total bytes and total count of this run to FILE",
+            "FILE",
+        );
+        opts.optflag("q", "quiet", "don't print warnings");
+        opts.optflag(
+            "",
+            "exit-zero-on-empty",
+            "exit 0 when no entries are left to show, instead of 4 (errors still exit 1)",
+        );
+        opts.optflag("", "profile", "print time spent in each phase to stderr");
+        opts.optflag(
+            "",
+            "dustignore",
+            "skip paths matched by .dustignore files found while walking",
+        );
+        opts.optflag(
+            "",
+            "deterministic",
+            "walk directories in name order so output is the same everywhere",
+        );
+        opts.optflag(
+            "",
+            "no-sort",
+            "keep entries in the order they were found, ignoring --sort and -r",
+        );
+        opts.optflag("", "by-discovery", "same as --no-sort");
+        opts.optflag("L", "dereference", "follow symbolic links");
+        opts.optflag(
+            "",
+            "dedup-targets",
+            "with -L, count each symlink target only once",
+        );
+        opts.optflag(
+            "",
+            "no-symlink-loops",
+            "with -L, don't enter a directory that was already counted through another path",
+        );
+        opts.optopt(
+            "",
+            "follow-limit",
+            "with -L, follow at most N nested symlinks and N distinct targets",
+            "N",
+        );
+        opts.optopt(
+            "",
+            "entry-limit-per-dir",
+            "stop counting a directory's entries after N, marking it as truncated",
+            "N",
+        );
+        opts.optflag(
+            "",
+            "fail-fast",
+            "exit on the first error while walking instead of skipping the path",
+        );
+        opts.optopt(
+            "",
+            "io-retries",
+            "retry interrupted stat calls N times (default 3)",
+            "N",
+        );
+        opts.optflag(
+            "",
+            "files-only-size",
+            "only count the size of regular files, not directories or special files",
+        );
+        opts.optflag(
+            "",
+            "exclude-pseudo",
+            "skip pseudo filesystems like /proc, /sys and /dev",
+        );
+        opts.optflag(
+            "",
+            "count-special",
+            "count the size of devices, fifos and sockets",
+        );
 
-        let matches = match opts.parse(&args.collect::<Vec<_>>()) {
+        let matches = match opts.parse(args.collect::<Vec<_>>()) {
             Ok(m) => m,
             Err(err) => {
                 eprintln!("could not parse args: {}", err);
@@ -37,130 +330,1958 @@ impl Opts {
         };
 
         if matches.opt_present("h") {
-            Self::print_usage(&name, &opts)
+            Self::print_usage(name, &opts)
+        };
+
+        if matches.opt_present("p") && matches.opt_present("sort") {
+            eprintln!("-p and --sort are mutually exclusive, use --sort path instead");
+            std::process::exit(1);
+        }
+        let sort = if matches.opt_present("p") {
+            vec![(SortKey::Path, Direction::Asc)]
+        } else {
+            match matches.opt_str("sort") {
+                Some(keys) => keys
+                    .split(',')
+                    .map(|key| match SortKey::parse(key.trim()) {
+                        Some(pair) => pair,
+                        None => {
+                            eprintln!("invalid sort key: {}", key);
+                            std::process::exit(1);
+                        }
+                    })
+                    .collect(),
+                None => vec![(SortKey::Size, Direction::Asc)],
+            }
+        };
+
+        if matches.opt_present("none-first") && matches.opt_present("none-last") {
+            eprintln!("--none-first and --none-last are mutually exclusive");
+            std::process::exit(1);
+        }
+
+        let columns = match matches.opt_str("columns") {
+            Some(list) => list
+                .split(',')
+                .map(|col| match Column::parse(col.trim()) {
+                    Some(col) => col,
+                    None => {
+                        eprintln!("invalid column: {}", col);
+                        std::process::exit(1);
+                    }
+                })
+                .collect(),
+            None if matches.opt_present("P") => {
+                vec![Column::Size, Column::Percent, Column::Count, Column::Path]
+            }
+            None => vec![Column::Size, Column::Count, Column::Path],
+        };
+
+        if matches.opt_present("age-ranges") && !matches.opt_present("age-buckets") {
+            eprintln!("--age-ranges needs --age-buckets");
+            std::process::exit(1);
+        }
+        let age_ranges = if matches.opt_present("age-buckets") {
+            let list = matches
+                .opt_str("age-ranges")
+                .unwrap_or_else(|| "1w,1mo,1y".into());
+            let mut ranges = list
+                .split(',')
+                .map(|age| match parse_age(age.trim()) {
+                    Some(d) => (age.trim().to_string(), d),
+                    None => {
+                        eprintln!("invalid age: {}", age);
+                        std::process::exit(1);
+                    }
+                })
+                .collect::<Vec<_>>();
+            ranges.sort_by_key(|(_, d)| *d);
+            Some(ranges)
+        } else {
+            None
+        };
+
+        let raw = matches.opt_present("raw");
+        let unit = matches.opt_str("unit").map(|u| {
+            match SIZE_UNITS[..7]
+                .iter()
+                .position(|s| s.eq_ignore_ascii_case(&u))
+            {
+                Some(order) => order,
+                None => {
+                    eprintln!("invalid unit: {}", u);
+                    std::process::exit(1);
+                }
+            }
+        });
+        if unit.is_some() && matches.opt_present("bytes") {
+            eprintln!("--bytes and --unit are mutually exclusive");
+            std::process::exit(1);
+        }
+        // like --human, a unit overrides the plain bytes of --raw
+        let human = matches.opt_present("human") || unit.is_some();
+        let mut format = Format {
+            unit,
+            bytes: (raw || matches.opt_present("bytes")) && !human,
+            separator: !raw && !matches.opt_present("no-separator"),
+            tabs: raw,
+            footer: !raw,
+            compact: matches.opt_present("compact"),
+            relative_time: matches.opt_present("relative-time"),
+            percent_precision: number(&matches, "percent-precision").unwrap_or(2),
+            nul: matches.opt_present("print0-records"),
+            crlf: false,
+            bom: false,
+        };
+        for enc in matches
+            .opt_strs("output-encoding")
+            .iter()
+            .flat_map(|s| s.split(','))
+        {
+            match enc.trim() {
+                "lf" => format.crlf = false,
+                "crlf" => format.crlf = true,
+                "utf8-bom" => format.bom = true,
+                enc => {
+                    eprintln!("invalid output encoding: {}", enc);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        let path_display = match matches.opt_str("path-display").as_deref() {
+            Some("name") => PathDisplay::Name,
+            None | Some("relative") => PathDisplay::Relative,
+            Some("absolute") => PathDisplay::Absolute,
+            Some(display) => {
+                eprintln!("invalid path display: {}", display);
+                std::process::exit(1);
+            }
+        };
+
+        let percent_base = match matches.opt_str("percent-base").as_deref() {
+            None | Some("scan") => PercentBase::Scan,
+            Some("displayed") => PercentBase::Displayed,
+            Some(base) => {
+                eprintln!("invalid percent base: {}", base);
+                std::process::exit(1);
+            }
         };
 
+        let rollups = [
+            ("by-day", Rollup::Day),
+            ("by-week", Rollup::Week),
+            ("by-month", Rollup::Month),
+        ];
+        let mut rollups = rollups.iter().filter(|(name, _)| matches.opt_present(name));
+        let rollup = rollups.next().map(|&(_, rollup)| rollup);
+        if rollups.next().is_some() {
+            eprintln!("only one of --by-day, --by-week and --by-month can be used");
+            std::process::exit(1);
+        }
+        if rollup.is_some() && age_ranges.is_some() {
+            eprintln!("--age-buckets can't be used with --by-day, --by-week or --by-month");
+            std::process::exit(1);
+        }
+
+        let follows = ["dedup-targets", "follow-limit", "no-symlink-loops"];
+        if let (false, Some(flag)) = (
+            matches.opt_present("L"),
+            follows.iter().find(|flag| matches.opt_present(flag)),
+        ) {
+            eprintln!("--{} only applies with -L", flag);
+            std::process::exit(1);
+        }
+
+        // these are tallied while walking, which a resumed path doesn't get
+        let walked = [
+            "ext-summary",
+            "age-buckets",
+            "by-day",
+            "by-week",
+            "by-month",
+            "dedup-targets",
+            "no-symlink-loops",
+            "follow-limit",
+            "entry-limit-per-dir",
+        ];
+        if let (true, Some(flag)) = (
+            matches.opt_present("resume"),
+            walked.iter().find(|flag| matches.opt_present(flag)),
+        ) {
+            eprintln!("--resume can't be used with --{}", flag);
+            std::process::exit(1);
+        }
+
+        let mut summary_format = matches.opt_str("summary-format");
+        if let Some(file) = matches.opt_str("format-file") {
+            if summary_format.is_some() {
+                eprintln!("--summary-format and --format-file can't be used together");
+                std::process::exit(1);
+            }
+            let data = match std::fs::read_to_string(&file) {
+                Ok(data) => data,
+                Err(err) => {
+                    eprintln!("cannot read format file {}: {}", file, err);
+                    std::process::exit(1);
+                }
+            };
+            let lines = data.lines().filter(|line| !line.starts_with('#'));
+            summary_format = Some(lines.collect::<Vec<_>>().join("\n"));
+        }
+        if let Some(template) = &summary_format {
+            let known = |name: &str| SUMMARY_FIELDS.contains(&name).then(String::new);
+            if let Err(err) = expand_template(template, known) {
+                eprintln!("invalid summary format: {}", err);
+                std::process::exit(1);
+            }
+        }
+
         Self {
-            name,
             reverse: matches.opt_present("r"),
-            percentage: matches.opt_present("P"),
-            path: matches.opt_present("p"),
-            min: matches.opt_get_default("m", 0.00).expect("min percentage"),
-            input: matches.free.get(0).cloned().unwrap_or_else(|| "*".into()),
+            columns,
+            percent_base,
+            format,
+            auto_strip: matches.opt_present("auto-strip"),
+            summary_format,
+            path_display,
+            sort,
+            none_first: matches.opt_present("none-first"),
+            ignore_case: matches.opt_present("f"),
+            min: number(&matches, "min").unwrap_or(0.00),
+            lump_below: number(&matches, "lump-below"),
+            prune_under: matches
+                .opt_str("prune-under")
+                .map(|s| match parse_size(&s) {
+                    Some(size) => size,
+                    None => {
+                        eprintln!("invalid size: {}", s);
+                        std::process::exit(1);
+                    }
+                }),
+            min_count: number(&matches, "min-count").unwrap_or(0),
+            max_entries: number(&matches, "max-entries"),
+            sample: number(&matches, "sample"),
+            seed: number(&matches, "seed"),
+            ext_summary: if matches.opt_present("ext-summary") {
+                Some(number(&matches, "ext-summary").unwrap_or(10))
+            } else {
+                None
+            },
+            age_ranges,
+            rollup,
+            checkpoint: matches.opt_str("checkpoint"),
+            resume: matches.opt_str("resume"),
+            append_log: matches.opt_str("append-log"),
+            quiet: matches.opt_present("q"),
+            exit_zero_on_empty: matches.opt_present("exit-zero-on-empty"),
+            profile: matches.opt_present("profile"),
+            dustignore: matches.opt_present("dustignore"),
+            deterministic: matches.opt_present("deterministic"),
+            no_sort: matches.opt_present("no-sort") || matches.opt_present("by-discovery"),
+            dereference: matches.opt_present("L"),
+            dedup_targets: matches.opt_present("dedup-targets"),
+            no_symlink_loops: matches.opt_present("no-symlink-loops"),
+            follow_limit: number(&matches, "follow-limit"),
+            count_special: matches.opt_present("count-special"),
+            exclude_pseudo: matches.opt_present("exclude-pseudo"),
+            files_only_size: matches.opt_present("files-only-size"),
+            entry_limit: number(&matches, "entry-limit-per-dir"),
+            fail_fast: matches.opt_present("fail-fast"),
+            io_retries: number(&matches, "io-retries").unwrap_or(3),
+            dedup_paths: matches.opt_present("deduplicate-paths"),
+            children: matches.opt_present("c"),
+            glob_root: matches.opt_str("glob-root").map(|dir| {
+                if !Path::new(&dir).is_dir() {
+                    eprintln!("glob root is not a directory: {}", dir);
+                    std::process::exit(1);
+                }
+                PathBuf::from(dir)
+            }),
+            inputs: if matches.free.is_empty() {
+                vec!["*".into()]
+            } else {
+                matches.free
+            },
         }
     }
 
     fn print_usage(name: &str, options: &Options) -> ! {
-        let brief = format!("usage: {} [FLAGS] path", name);
+        let brief = format!("usage: {} [FLAGS] [path...]", name);
         print!("{}", options.usage(&brief));
         std::process::exit(0)
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum SortKey {
+    Size,
+    Path,
+    Count,
+    Ext,
+    Mtime,
+    Random,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Direction {
+    Asc,
+    Desc,
+}
+
+impl Direction {
+    fn apply(self, ord: Ordering) -> Ordering {
+        match self {
+            Direction::Asc => ord,
+            Direction::Desc => ord.reverse(),
+        }
+    }
+}
+
+impl SortKey {
+    // `key` or `key:asc` or `key:desc`
+    fn parse(s: &str) -> Option<(Self, Direction)> {
+        let mut parts = s.splitn(2, ':');
+        let key = match parts.next()? {
+            "size" => SortKey::Size,
+            "path" => SortKey::Path,
+            "count" => SortKey::Count,
+            "ext" => SortKey::Ext,
+            "mtime" => SortKey::Mtime,
+            "random" => SortKey::Random,
+            _ => return None,
+        };
+        let dir = match parts.next() {
+            None | Some("asc") => Direction::Asc,
+            Some("desc") => Direction::Desc,
+            _ => return None,
+        };
+        Some((key, dir))
+    }
+
+    // the direction only applies to the key itself, not to its built-in tiebreakers
+    fn compare(self, dir: Direction, l: &Entry, r: &Entry, opts: &Opts) -> Ordering {
+        match self {
+            SortKey::Size => dir.apply(l.size.cmp(&r.size)),
+            SortKey::Path if opts.ignore_case => {
+                let lower = |p: &Path| p.to_string_lossy().to_lowercase();
+                let ord = lower(&l.display).cmp(&lower(&r.display));
+                dir.apply(ord.then(l.display.cmp(&r.display)))
+            }
+            SortKey::Path => dir.apply(l.display.cmp(&r.display)),
+            SortKey::Count => dir.apply(l.count.cmp(&r.count)),
+            // largest first within the same extension
+            SortKey::Ext => dir
+                .apply(l.extension().cmp(&r.extension()))
+                .then(r.size.cmp(&l.size)),
+            SortKey::Mtime => {
+                let ord = match (l.mtime, r.mtime) {
+                    (Some(l), Some(r)) => dir.apply(l.cmp(&r)),
+                    (None, None) => Ordering::Equal,
+                    (None, Some(..)) if opts.none_first => Ordering::Less,
+                    (None, Some(..)) => Ordering::Greater,
+                    (Some(..), None) if opts.none_first => Ordering::Greater,
+                    (Some(..), None) => Ordering::Less,
+                };
+                ord.then_with(|| l.display.cmp(&r.display))
+            }
+            SortKey::Random => dir.apply(l.rank.cmp(&r.rank)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Format {
+    bytes: bool,
+    unit: Option<usize>,
+    separator: bool,
+    tabs: bool,
+    footer: bool,
+    compact: bool,
+    relative_time: bool,
+    percent_precision: usize,
+    nul: bool,
+    crlf: bool,
+    bom: bool,
+}
+
+impl Format {
+    fn println(&self, line: &str) {
+        let end = match (self.nul, self.crlf) {
+            (true, _) => "\0",
+            (false, true) => "\r\n",
+            (false, false) => "\n",
+        };
+        print!("{}{}", line, end);
+    }
+
+    fn size(&self, n: u64) -> String {
+        if self.bytes {
+            n.to_string()
+        } else if self.compact {
+            format_size(n, self.unit).replace(' ', "")
+        } else {
+            format_size(n, self.unit)
+        }
+    }
+
+    // UTC, or how long ago with --relative-time
+    fn time(&self, t: SystemTime) -> String {
+        let secs = unix_secs(t);
+        if !self.relative_time {
+            let (y, m, d) = civil_from_days(secs.div_euclid(24 * 60 * 60));
+            let secs = secs.rem_euclid(24 * 60 * 60);
+            return format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}",
+                y,
+                m,
+                d,
+                secs / 3600,
+                secs / 60 % 60
+            );
+        }
+
+        // clock skew can put it in the future
+        let ago = unix_secs(SystemTime::now()) - secs;
+        if ago < 0 {
+            format!("in {}", format_duration(-ago as u64))
+        } else {
+            format_duration(ago as u64)
+        }
+    }
+
+    fn percent(&self, n: f64) -> String {
+        format!("{:.*}%", self.percent_precision, n)
+    }
+
+    fn count(&self, n: u64) -> String {
+        if self.separator {
+            format_count(n)
+        } else {
+            n.to_string()
+        }
+    }
+}
+
+// calendar dates are in UTC
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Rollup {
+    Day,
+    Week,
+    Month,
+}
+
+impl Rollup {
+    fn key(self, t: SystemTime) -> i64 {
+        let days = unix_secs(t).div_euclid(24 * 60 * 60);
+        match self {
+            Rollup::Day => days,
+            // the epoch was a thursday, weeks start on monday
+            Rollup::Week => days - (days + 3).rem_euclid(7),
+            Rollup::Month => {
+                let (y, m, _) = civil_from_days(days);
+                y * 12 + i64::from(m) - 1
+            }
+        }
+    }
+
+    fn label(self, key: i64) -> String {
+        match self {
+            Rollup::Day | Rollup::Week => {
+                let (y, m, d) = civil_from_days(key);
+                format!("{:04}-{:02}-{:02}", y, m, d)
+            }
+            Rollup::Month => format!("{:04}-{:02}", key.div_euclid(12), key.rem_euclid(12) + 1),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum PathDisplay {
+    Name,
+    Relative,
+    Absolute,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum PercentBase {
+    Scan,
+    Displayed,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Column {
+    Size,
+    Percent,
+    Count,
+    Mtime,
+    Path,
+}
+
+impl Column {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "size" => Some(Column::Size),
+            "percent" => Some(Column::Percent),
+            "count" => Some(Column::Count),
+            "mtime" => Some(Column::Mtime),
+            "path" => Some(Column::Path),
+            _ => None,
+        }
+    }
+
+    fn cell(self, entry: &Entry, base: u64, strip: &Path, fmt: &Format) -> String {
+        let path = entry.display.strip_prefix(strip).unwrap_or(&entry.display);
+        match self {
+            Column::Size => fmt.size(entry.size),
+            Column::Percent => fmt.percent(percent(entry.size, base)),
+            Column::Count => fmt.count(entry.count),
+            Column::Mtime => match entry.mtime {
+                Some(t) => fmt.time(t),
+                None => "-".into(),
+            },
+            Column::Path => {
+                let mut s = path.display().to_string();
+                if entry.dir {
+                    s.push(std::path::MAIN_SEPARATOR)
+                }
+                // raw rows are for parsing, so the path field stays just the path
+                if fmt.tabs {
+                    return s;
+                }
+                // display() replaced the invalid bytes, so make that visible
+                if entry.path.to_str().is_none() {
+                    s.push_str(" (not utf-8)")
+                }
+                if entry.truncated {
+                    s.push_str(" (truncated)")
+                }
+                s
+            }
+        }
+    }
+
+    fn min_width(self, fmt: &Format) -> usize {
+        match self {
+            Column::Size => 10,
+            // room for `00.00%`, with however many decimals
+            Column::Percent => 3 + fmt.percent_precision + (fmt.percent_precision > 0) as usize,
+            _ => 0,
+        }
+    }
+}
+
 fn main() {
     let opts = Opts::parse();
-    let dirs = glob(&opts.input).unwrap().filter_map(|p| p.ok());
-    let (total_size, total_count, mut entries) = walk_entries(dirs);
-    let total_count = format_count(total_count);
-    let count_width = total_count.len();
 
-    if opts.path {
-        entries.sort_unstable_by(|l, r| l.path.cmp(&r.path))
-    } else {
-        entries.sort_unstable_by_key(|e| e.size)
+    let start = Instant::now();
+    let mut dirs = expand_globs(&opts.inputs, opts.glob_root.as_deref(), opts.fail_fast);
+    if opts.dedup_paths {
+        dirs = dedup_paths(dirs, opts.quiet);
+    }
+    // the directory itself still counts towards the totals
+    let (mut own, mut ignored_above) = ((0, 0), vec![]);
+    if opts.children {
+        if dirs.len() != 1 {
+            eprintln!(
+                "-c takes a single directory, but {} paths matched",
+                dirs.len()
+            );
+            std::process::exit(1);
+        }
+        let dir = dirs.remove(0);
+        if !dir.is_dir() {
+            eprintln!("-c takes a single directory, {} isn't one", dir.display());
+            std::process::exit(1);
+        }
+        let md = match opts.dereference {
+            true => dir.metadata(),
+            false => dir.symlink_metadata(),
+        };
+        own = md.map_or((0, 0), |md| (counted_size(&md, &opts), 1));
+        dirs = match std::fs::read_dir(&dir) {
+            Ok(children) => children
+                .filter_map(|e| match e {
+                    Ok(e) => Some(e.path()),
+                    Err(err) if opts.fail_fast => {
+                        eprintln!("cannot read {}: {}", dir.display(), err);
+                        std::process::exit(1);
+                    }
+                    Err(..) => None,
+                })
+                .collect(),
+            Err(err) => {
+                eprintln!("cannot read {}: {}", dir.display(), err);
+                std::process::exit(1);
+            }
+        };
+        if opts.deterministic {
+            dirs.sort();
+        }
+
+        // the same rules the walk applies to the directory's entries, in the same order
+        if opts.dustignore {
+            if let Some(patterns) = read_dustignore(&dir, opts.quiet) {
+                dirs.retain(|p| !dustignore_matches(&dir, &patterns, p));
+                ignored_above.push((dir.clone(), patterns));
+            }
+        }
+        if let Some(limit) = opts.entry_limit.filter(|&limit| dirs.len() > limit) {
+            if !opts.quiet {
+                eprintln!("truncated {} after {} entries", dir.display(), limit);
+            }
+            dirs.truncate(limit);
+        }
+        dirs.sort();
+    }
+    let glob_time = start.elapsed();
+
+    let start = Instant::now();
+    let mut state = WalkState {
+        ignored_above,
+        ..WalkState::default()
+    };
+    let (total_size, total_count, mut entries) = walk_entries(dirs, &opts, &mut state);
+    let (total_size, total_count) = (total_size + own.0, total_count + own.1);
+    let walk_time = start.elapsed();
+
+    if let Some(file) = &opts.append_log {
+        append_log(file, total_size, total_count);
+    }
+
+    let start = Instant::now();
+    match opts.path_display {
+        PathDisplay::Relative => {
+            if let Some(root) = &opts.glob_root {
+                for e in &mut entries {
+                    e.display = e.path.strip_prefix(root).unwrap_or(&e.path).to_path_buf();
+                }
+            }
+        }
+        PathDisplay::Absolute => {
+            let cwd = env::current_dir().unwrap_or_default();
+            for e in &mut entries {
+                e.display = e.path.canonicalize().unwrap_or_else(|_| cwd.join(&e.path));
+            }
+        }
+        PathDisplay::Name => display_names(&mut entries, opts.quiet),
+    }
+
+    let mut rng = Rng(opts.seed.unwrap_or_else(|| {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        now.map_or(0, |d| d.as_nanos() as u64) ^ u64::from(std::process::id())
+    }));
+    for e in &mut entries {
+        e.rank = rng.next();
+    }
+
+    sort_entries(&mut entries, &opts);
+
+    entries.retain(|e| {
+        percent(e.size, total_size) >= opts.min
+            && e.count >= opts.min_count
+            && !(e.dir && opts.prune_under.is_some_and(|min| e.size < min))
+    });
+
+    if let Some(n) = opts.sample {
+        // a partial shuffle of the indices picks n of them, the entries keep their order
+        let mut picks = (0..entries.len()).collect::<Vec<_>>();
+        for i in 0..n.min(picks.len()) {
+            let j = i + rng.below(picks.len() - i);
+            picks.swap(i, j);
+        }
+        let mut keep = vec![false; entries.len()];
+        for &i in picks.iter().take(n) {
+            keep[i] = true;
+        }
+        let mut keep = keep.into_iter();
+        entries.retain(|_| keep.next().unwrap_or_default());
+    }
+
+    // the other row takes the same sizes, so the base is the same before and after
+    let base = percent_base(&opts, &entries, total_size);
+    let lumped = |e: &Entry| {
+        opts.lump_below
+            .is_some_and(|lump| percent(e.size, base) < lump)
     };
+    // a single small entry reads better as itself
+    if entries.iter().filter(|e| lumped(e)).count() > 1 {
+        let (small, rest): (Vec<_>, Vec<_>) = entries.into_iter().partition(lumped);
+        entries = rest;
+        let path = common_prefix(&small).join(format!("<other ({} entries)>", small.len()));
+        let other = Entry {
+            display: path.clone(),
+            path,
+            size: small.iter().map(|e| e.size).sum(),
+            count: small.iter().map(|e| e.count).sum(),
+            dir: false,
+            mtime: small.iter().filter_map(|e| e.mtime).max(),
+            truncated: small.iter().any(|e| e.truncated),
+            rank: rng.next(),
+        };
+        entries.push(other);
+        // slot it back in where it sorts
+        let compare = |l: &Entry, r: &Entry| match opts.reverse {
+            true => compare_entries(r, l, &opts),
+            false => compare_entries(l, r, &opts),
+        };
+        if !opts.no_sort {
+            entries.sort_by(compare);
+        }
+    }
+    let sort_time = start.elapsed();
+
+    let start = Instant::now();
+    if opts.format.bom {
+        print!("\u{feff}");
+    }
+    if let Some(ranges) = &opts.age_ranges {
+        print_age_buckets(ranges, &state.ages, &opts.format)
+    } else if let Some(rollup) = opts.rollup {
+        print_rollup(rollup, &state.dates, &opts.format)
+    } else {
+        print_entries(&opts, &entries, total_size, total_count)
+    }
+    if let Some(n) = opts.ext_summary {
+        print_ext_summary(&state.extensions, n, &opts.format);
+    }
+    let print_time = start.elapsed();
+
+    if opts.profile {
+        print_profile(&[
+            ("glob", glob_time),
+            ("walk", walk_time),
+            ("sort", sort_time),
+            ("print", print_time),
+        ]);
+    }
+
+    if entries.is_empty() {
+        if !opts.quiet {
+            eprintln!("no entries matched filters");
+        }
+        if !opts.exit_zero_on_empty {
+            std::process::exit(4);
+        }
+    }
+}
+
+fn compare_entries(l: &Entry, r: &Entry, opts: &Opts) -> Ordering {
+    opts.sort.iter().fold(Ordering::Equal, |ord, &(key, dir)| {
+        ord.then_with(|| key.compare(dir, l, r, opts))
+    })
+}
 
-    if opts.reverse {
+fn sort_entries(entries: &mut [Entry], opts: &Opts) {
+    let compare = |l: &Entry, r: &Entry| compare_entries(l, r, opts);
+    // ties keep the (sorted) discovery order
+    if opts.no_sort {
+        // leave them as walk_entries found them
+    } else if opts.deterministic {
+        entries.sort_by(compare)
+    } else {
+        entries.sort_unstable_by(compare)
+    }
+
+    if opts.reverse && !opts.no_sort {
         entries.reverse();
     }
+}
 
-    for entry in entries {
-        let p = 100.0 * entry.size as f64 / total_size as f64;
-        if p < opts.min {
-            continue;
+// the last component of each path, or as many as it takes to tell the same names apart
+fn display_names(entries: &mut [Entry], quiet: bool) {
+    let suffix = |p: &Path, n: usize| -> PathBuf {
+        let parts = p.components().collect::<Vec<_>>();
+        parts[parts.len().saturating_sub(n)..].iter().collect()
+    };
+
+    let mut lengths = vec![1; entries.len()];
+    loop {
+        let mut seen: HashMap<PathBuf, Vec<usize>> = HashMap::new();
+        for (i, e) in entries.iter().enumerate() {
+            seen.entry(suffix(&e.path, lengths[i])).or_default().push(i);
         }
 
-        print!("{:>10} ", format_size(entry.size));
-        if opts.percentage {
-            print!(" {} ", format!("{:>5.2}%", p));
+        let mut changed = false;
+        for i in seen.values().filter(|same| same.len() > 1).flatten() {
+            if lengths[*i] < entries[*i].path.components().count() {
+                lengths[*i] += 1;
+                changed = true;
+            }
         }
 
-        print!(" {:>size$} ", format_count(entry.count), size = count_width);
+        if !changed {
+            if !quiet {
+                for (name, same) in seen.iter().filter(|(_, same)| same.len() > 1) {
+                    eprintln!("{} entries are shown as {}", same.len(), name.display());
+                }
+            }
+            break;
+        }
+    }
 
-        if entry.path.is_dir() {
-            println!(
-                " {}{}",
-                entry.path.display().to_string(),
-                std::path::MAIN_SEPARATOR,
-            );
-        } else {
-            println!(" {}", entry.path.display().to_string());
+    for (e, n) in entries.iter_mut().zip(lengths) {
+        e.display = suffix(&e.path, n);
+    }
+}
+
+// the deepest directory that contains every entry
+fn common_prefix(entries: &[Entry]) -> PathBuf {
+    let mut parents = entries
+        .iter()
+        .map(|e| e.display.parent().unwrap_or(&e.display));
+    let first = match parents.next() {
+        Some(first) => first.to_path_buf(),
+        None => return PathBuf::new(),
+    };
+    parents.fold(first, |prefix, parent| {
+        prefix
+            .components()
+            .zip(parent.components())
+            .take_while(|(l, r)| l == r)
+            .map(|(l, _)| l)
+            .collect()
+    })
+}
+
+fn print_entries(opts: &Opts, entries: &[Entry], total_size: u64, total_count: u64) {
+    // raw rows keep their full paths, there's no header line to say what they'd be relative to
+    let strip = if opts.auto_strip && !opts.format.tabs {
+        common_prefix(entries)
+    } else {
+        PathBuf::new()
+    };
+    if !strip.as_os_str().is_empty() {
+        opts.format.println(&format!(
+            ".{} relative to {}",
+            std::path::MAIN_SEPARATOR,
+            strip.display()
+        ));
+    }
+
+    let base = percent_base(opts, entries, total_size);
+
+    let rows = entries
+        .iter()
+        .map(|e| {
+            opts.columns
+                .iter()
+                .map(|c| c.cell(e, base, &strip, &opts.format))
+                .collect()
+        })
+        .collect::<Vec<Vec<_>>>();
+    let totals = opts
+        .columns
+        .iter()
+        .map(|c| match c {
+            Column::Size => opts.format.size(total_size),
+            Column::Count => opts.format.count(total_count),
+            _ => String::new(),
+        })
+        .collect::<Vec<_>>();
+
+    match &opts.summary_format {
+        Some(template) => {
+            print_table(&opts.columns, &rows, None, &opts.format);
+            let summary = expand_template(template, |name| {
+                let value = match name {
+                    "total_size" => format_size(total_size, opts.format.unit),
+                    "total_bytes" => total_size.to_string(),
+                    "total_count" => opts.format.count(total_count),
+                    "entry_count" => entries.len().to_string(),
+                    _ => return None,
+                };
+                Some(value)
+            });
+            for line in summary.expect("validated summary format").lines() {
+                opts.format.println(line)
+            }
         }
+        None => print_table(&opts.columns, &rows, Some(&totals), &opts.format),
     }
+}
 
-    let p = if opts.percentage { 8 } else { 0 } + 1;
-    use std::iter::repeat;
-    println!(
-        "{}",
-        repeat("-")
-            .take(10)
-            .chain(repeat(" ").take(p + 1))
-            .chain(repeat("-").take(count_width))
-            .collect::<String>()
-    );
+const SUMMARY_FIELDS: [&str; 4] = ["total_size", "total_bytes", "total_count", "entry_count"];
+
+// `{name}` is replaced by lookup(name), `{{` and `}}` are literal braces
+fn expand_template<F>(template: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{')
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}')
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(format!("unclosed placeholder: {{{}", name)),
+                    }
+                }
+                match lookup(&name) {
+                    Some(value) => out.push_str(&value),
+                    None => return Err(format!("unknown placeholder: {{{}}}", name)),
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+// (label, size, count) rows
+fn print_buckets(buckets: &[(String, u64, u64)], fmt: &Format) {
+    let columns = [Column::Size, Column::Count, Column::Path];
+    let rows = buckets
+        .iter()
+        .map(|(label, size, count)| vec![fmt.size(*size), fmt.count(*count), label.clone()])
+        .collect::<Vec<_>>();
+    let (size, count) = buckets
+        .iter()
+        .fold((0, 0), |(s, c), (_, size, count)| (s + size, c + count));
+    let totals = vec![fmt.size(size), fmt.count(count), String::new()];
+    print_table(&columns, &rows, Some(&totals), fmt);
+}
 
-    println!(
-        "{:>10} {:>offset$}",
-        format_size(total_size),
-        total_count,
-        offset = p + count_width
+fn print_age_buckets(ranges: &[(String, Duration)], ages: &[(u64, u64)], fmt: &Format) {
+    let labels = (0..=ranges.len()).map(|i| match i {
+        0 => format!("< {}", ranges[0].0),
+        i if i == ranges.len() => format!(">= {}", ranges[i - 1].0),
+        i => format!("{} - {}", ranges[i - 1].0, ranges[i].0),
+    });
+    let buckets = labels
+        .chain(std::iter::once("<unknown>".to_string()))
+        .zip(ages)
+        .map(|(label, &(size, count))| (label, size, count))
+        .collect::<Vec<_>>();
+    print_buckets(&buckets, fmt);
+}
+
+fn print_rollup(rollup: Rollup, dates: &BTreeMap<i64, (u64, u64)>, fmt: &Format) {
+    let buckets = dates
+        .iter()
+        .map(|(&key, &(size, count))| match key {
+            i64::MAX => ("<unknown>".to_string(), size, count),
+            key => (rollup.label(key), size, count),
+        })
+        .collect::<Vec<_>>();
+    print_buckets(&buckets, fmt);
+}
+
+fn print_table(columns: &[Column], rows: &[Vec<String>], totals: Option<&[String]>, fmt: &Format) {
+    let widths = columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            rows.iter()
+                .map(|row| &row[i])
+                .chain(totals.map(|t| &t[i]))
+                .map(|cell| cell.chars().count())
+                .fold(c.min_width(fmt), std::cmp::max)
+        })
+        .collect::<Vec<_>>();
+
+    for row in rows {
+        print_row(row, &widths, columns, fmt);
+    }
+
+    let totals = match totals {
+        Some(totals) if fmt.footer => totals,
+        _ => return,
+    };
+
+    if columns
+        .iter()
+        .any(|c| *c == Column::Size || *c == Column::Count)
+    {
+        let separator = columns
+            .iter()
+            .zip(&widths)
+            .map(|(c, &w)| match c {
+                Column::Size | Column::Count => "-".repeat(w),
+                _ => " ".repeat(w),
+            })
+            .collect::<Vec<_>>();
+        if !fmt.compact && !fmt.nul {
+            print_row(&separator, &widths, columns, fmt);
+        }
+        print_row(totals, &widths, columns, fmt);
+    }
+}
+
+fn print_row(row: &[String], widths: &[usize], columns: &[Column], fmt: &Format) {
+    if fmt.tabs {
+        fmt.println(&row.join("\t"));
+        return;
+    }
+
+    if fmt.compact {
+        let cells = row.iter().filter(|c| !c.is_empty());
+        fmt.println(&cells.map(String::as_str).collect::<Vec<_>>().join(" "));
+        return;
+    }
+
+    // leave off blank trailing cells and the padding of the last one, but don't trim the
+    // line itself as paths can end in whitespace
+    let len = row
+        .iter()
+        .rposition(|c| !c.trim().is_empty())
+        .map_or(0, |i| i + 1);
+    let line = row[..len]
+        .iter()
+        .zip(widths)
+        .zip(columns)
+        .enumerate()
+        .map(|(i, ((cell, &w), c))| match c {
+            Column::Path if i + 1 == len => cell.clone(),
+            Column::Path => format!("{:<w$}", cell, w = w),
+            _ => format!("{:>w$}", cell, w = w),
+        })
+        .collect::<Vec<_>>()
+        .join("  ");
+    fmt.println(&line);
+}
+
+fn print_ext_summary(extensions: &HashMap<String, (u64, u64)>, n: usize, fmt: &Format) {
+    let mut extensions = extensions.iter().collect::<Vec<_>>();
+    extensions.sort_unstable_by(|(lk, (l, _)), (rk, (r, _))| r.cmp(l).then(lk.cmp(rk)));
+
+    let rows = extensions
+        .into_iter()
+        .take(n)
+        .map(|(ext, (size, count))| vec![fmt.size(*size), fmt.count(*count), ext.clone()])
+        .collect::<Vec<_>>();
+
+    fmt.println("");
+    print_table(
+        &[Column::Size, Column::Count, Column::Path],
+        &rows,
+        None,
+        fmt,
     );
 }
 
+fn print_profile(phases: &[(&str, Duration)]) {
+    let total: Duration = phases.iter().map(|(_, d)| *d).sum();
+    for (name, d) in phases.iter().chain(&[("total", total)]) {
+        eprintln!("{:>6}: {:>10.3}ms", name, d.as_secs_f64() * 1000.0);
+    }
+}
+
+fn percent_base(opts: &Opts, entries: &[Entry], total_size: u64) -> u64 {
+    match opts.percent_base {
+        PercentBase::Scan => total_size,
+        PercentBase::Displayed => entries.iter().map(|e| e.size).sum(),
+    }
+}
+
+fn percent(size: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    100.0 * size as f64 / total as f64
+}
+
+fn expand_globs(patterns: &[String], root: Option<&Path>, fail_fast: bool) -> Vec<PathBuf> {
+    // the root is taken literally, only the pattern under it is a glob
+    let root = root.map(|root| match root.to_str() {
+        Some(s) => Pattern::escape(s),
+        None => {
+            eprintln!("glob root is not utf-8: {}", root.display());
+            std::process::exit(1);
+        }
+    });
+    let mut seen = HashSet::new();
+    let mut paths = vec![];
+    for pattern in patterns.iter().flat_map(|p| expand_braces(p)) {
+        let pattern = match &root {
+            Some(root) if Path::new(&pattern).is_relative() => Path::new(root)
+                .join(&pattern)
+                .to_string_lossy()
+                .into_owned(),
+            _ => pattern,
+        };
+        let matches = match glob(&pattern) {
+            Ok(m) => m,
+            Err(err) => {
+                eprintln!("invalid pattern '{}': {}", pattern, err);
+                std::process::exit(1);
+            }
+        };
+        let matches = matches.filter_map(|p| match p {
+            Ok(path) => Some(path),
+            Err(err) if fail_fast => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            Err(..) => None,
+        });
+        for path in matches {
+            if seen.insert(path.clone()) {
+                paths.push(path)
+            }
+        }
+    }
+    paths
+}
+
+// drops paths that resolve to one already given, or that live under another given path
+fn dedup_paths(paths: Vec<PathBuf>, quiet: bool) -> Vec<PathBuf> {
+    let resolved = paths
+        .into_iter()
+        .map(|p| {
+            let real = p.canonicalize().unwrap_or_else(|_| p.clone());
+            (p, real)
+        })
+        .collect::<Vec<_>>();
+
+    let mut kept: Vec<&(PathBuf, PathBuf)> = vec![];
+    for pair @ (path, real) in &resolved {
+        if let Some((other, _)) = kept.iter().find(|(_, r)| r == real) {
+            if !quiet {
+                eprintln!("skipping {}, same as {}", path.display(), other.display());
+            }
+            continue;
+        }
+
+        let ancestor = resolved
+            .iter()
+            .find(|(_, r)| r != real && real.starts_with(r));
+        if let Some((other, _)) = ancestor {
+            if !quiet {
+                eprintln!(
+                    "skipping {}, already counted under {}",
+                    path.display(),
+                    other.display()
+                );
+            }
+            continue;
+        }
+        kept.push(pair)
+    }
+    kept.into_iter().map(|(p, _)| p.clone()).collect()
+}
+
+// `{src,tests}/*.rs` -> `src/*.rs`, `tests/*.rs`. unbalanced braces are left alone
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let mut start = 0;
+    while let Some(open) = pattern[start..].find('{').map(|i| i + start) {
+        let (mut depth, mut bounds, mut close) = (0, vec![open], None);
+        for (i, c) in pattern[open..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = Some(open + i);
+                        break;
+                    }
+                }
+                ',' if depth == 1 => bounds.push(open + i),
+                _ => {}
+            }
+        }
+
+        match close {
+            Some(close) if bounds.len() > 1 => {
+                bounds.push(close);
+                let (head, tail) = (&pattern[..open], &pattern[close + 1..]);
+                return bounds
+                    .windows(2)
+                    .flat_map(|w| {
+                        expand_braces(&format!("{}{}{}", head, &pattern[w[0] + 1..w[1]], tail))
+                    })
+                    .collect();
+            }
+            _ => start = open + 1,
+        }
+    }
+    vec![pattern.to_string()]
+}
+
 #[derive(Debug)]
 struct Entry {
     path: PathBuf,
     size: u64,
     count: u64,
+    dir: bool,
+    mtime: Option<SystemTime>, // newest in the subtree
+    display: PathBuf,          // what gets shown, and sorted on, per --path-display
+    truncated: bool,           // size and count are only lower bounds
+    rank: u64,                 // what --sort random orders by
+}
+
+impl Entry {
+    // directories don't have an extension, so they all group together
+    fn extension(&self) -> Option<String> {
+        if self.dir {
+            return None;
+        }
+        let ext = self
+            .path
+            .extension()
+            .map(|s| s.to_string_lossy().to_lowercase());
+        Some(ext.unwrap_or_default())
+    }
+}
+
+// state shared across all of the roots
+#[derive(Default)]
+struct WalkState {
+    // (device, inode) of everything already counted with --dedup-targets
+    seen: HashSet<(u64, u64)>,
+    // extension -> (size, count) of the files, for --ext-summary
+    extensions: HashMap<String, (u64, u64)>,
+    // (size, count) per --age-buckets range, the last one is for unknown mtimes
+    ages: Vec<(u64, u64)>,
+    // distinct symlink targets followed, for --follow-limit
+    followed: HashSet<PathBuf>,
+    // (device, inode) of the directories already entered, for --no-symlink-loops
+    visited: HashSet<(u64, u64)>,
+    // mount points of pseudo filesystems, for --exclude-pseudo
+    pseudo: HashSet<PathBuf>,
+    // whether the current root had a directory cut short by --entry-limit-per-dir
+    truncated: bool,
+    // Rollup::key -> (size, count) of the files, i64::MAX is for unknown mtimes
+    dates: BTreeMap<i64, (u64, u64)>,
+    // .dustignore patterns from above the roots, for -c listing a directory's children
+    ignored_above: Vec<(PathBuf, Vec<Pattern>)>,
 }
 
-fn walk_entries<I>(paths: I) -> (u64, u64, Vec<Entry>)
+fn walk_entries<I>(paths: I, opts: &Opts, state: &mut WalkState) -> (u64, u64, Vec<Entry>)
 where
     I: IntoIterator<Item = PathBuf>, // TODO figure out how to borrow this as a &'a Path
 {
-    paths.into_iter().map(|p| (get_sizes(&p), p)).fold(
-        (0, 0, vec![]),
-        |(total_size, total_count, mut entries), ((size, count), path)| {
-            if path.exists() {
-                entries.push(Entry { path, size, count })
+    if opts.exclude_pseudo {
+        state.pseudo = pseudo_mounts();
+    }
+    if let Some(ranges) = &opts.age_ranges {
+        state.ages = vec![(0, 0); ranges.len() + 2];
+    }
+
+    // checkpoints are per path given on the command line (after globbing), so an
+    // interrupted scan only redoes the paths it hadn't finished
+    let settings = checkpoint_settings(opts);
+    let resumed = opts
+        .resume
+        .as_ref()
+        .map(|file| read_checkpoint(file, &settings))
+        .unwrap_or_default();
+    let mut checkpoint = opts
+        .checkpoint
+        .as_ref()
+        .or(opts.resume.as_ref())
+        .map(|file| {
+            let append = opts.resume.as_ref() == Some(file);
+            let mut checkpoint = open_checkpoint(file, append);
+            if !append {
+                write_checkpoint_header(&mut checkpoint, &settings)
             }
-            (total_size + size, total_count + count, entries)
-        },
-    )
+            (checkpoint, append)
+        });
+
+    let mut skipped = 0;
+    let (total_size, total_count, entries) = paths
+        .into_iter()
+        .map(|p| {
+            let (sizes, done, truncated) = match resumed.get(&p) {
+                Some(&sizes) => (sizes, true, false),
+                None => (get_sizes(&p, opts, state), false, state.truncated),
+            };
+            match &mut checkpoint {
+                // it's already in the file we're appending to
+                Some((_, true)) if done => {}
+                Some((file, _)) => write_checkpoint(file, &p, sizes),
+                None => {}
+            }
+            (sizes, truncated, p)
+        })
+        .fold(
+            (0, 0, vec![]),
+            |(total_size, total_count, mut entries), ((size, count, mtime), truncated, path)| {
+                // one more stat per root, for whether it's there and what it is
+                if let Ok(md) = path.metadata() {
+                    if opts.max_entries.is_some_and(|max| entries.len() >= max) {
+                        skipped += 1;
+                    } else {
+                        let dir = md.is_dir();
+                        entries.push(Entry {
+                            truncated,
+                            rank: 0,
+                            display: path.clone(),
+                            path,
+                            size,
+                            count,
+                            dir,
+                            mtime,
+                        })
+                    }
+                }
+                (total_size + size, total_count + count, entries)
+            },
+        );
+
+    if skipped > 0 && !opts.quiet {
+        eprintln!(
+            "entries truncated to {}, totals include {} more",
+            entries.len(),
+            skipped
+        );
+    }
+    (total_size, total_count, entries)
 }
 
-fn get_sizes(path: &Path) -> (u64, u64) {
-    walkdir::WalkDir::new(path)
+// splitmix64, which is plenty for shuffling a listing
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // slightly biased for huge n, which doesn't matter here
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// timestamp,total_bytes,total_count, with the timestamp in UTC
+fn append_log(file: &str, total_size: u64, total_count: u64) {
+    use std::io::{ErrorKind, Write};
+    let secs = unix_secs(SystemTime::now());
+    let (y, m, d) = civil_from_days(secs.div_euclid(24 * 60 * 60));
+    let secs = secs.rem_euclid(24 * 60 * 60);
+    let mut line = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z,{},{}\n",
+        y,
+        m,
+        d,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        total_size,
+        total_count
+    );
+
+    // only the run that creates the file writes the header
+    let open = |new| {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create_new(new)
+            .open(file)
+    };
+    let res = match open(true) {
+        Ok(log) => {
+            line.insert_str(0, "timestamp,total_bytes,total_count\n");
+            Ok(log)
+        }
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => open(false),
+        Err(err) => Err(err),
+    };
+    let mut log = match res {
+        Ok(log) => log,
+        Err(err) => {
+            eprintln!("cannot open log {}: {}", file, err);
+            std::process::exit(1);
+        }
+    };
+
+    // one write, so lines from runs at the same time don't interleave
+    if let Err(err) = log.write_all(line.as_bytes()) {
+        eprintln!("cannot write log {}: {}", file, err);
+        std::process::exit(1);
+    }
+}
+
+type Sizes = (u64, u64, Option<SystemTime>);
+
+// the options that change what a path adds up to, which a resumed scan has to share
+fn checkpoint_settings(opts: &Opts) -> String {
+    let flags = [
+        ("dereference", opts.dereference),
+        ("files-only-size", opts.files_only_size),
+        ("count-special", opts.count_special),
+        ("dustignore", opts.dustignore),
+        ("exclude-pseudo", opts.exclude_pseudo),
+        ("dedup-targets", opts.dedup_targets),
+        ("no-symlink-loops", opts.no_symlink_loops),
+    ];
+    let limits = [
+        ("follow-limit", opts.follow_limit),
+        ("entry-limit-per-dir", opts.entry_limit),
+    ];
+    let flags = flags
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| name.to_string());
+    let limits = limits
+        .iter()
+        .filter_map(|(name, n)| n.map(|n| format!("{}={}", name, n)));
+    let settings = flags.chain(limits).collect::<Vec<_>>();
+    match settings.is_empty() {
+        true => "# dust checkpoint: defaults".into(),
+        false => format!("# dust checkpoint: {}", settings.join(",")),
+    }
+}
+
+fn write_checkpoint_header(file: &mut std::fs::File, settings: &str) {
+    use std::io::Write;
+    if let Err(err) = writeln!(file, "{}", settings) {
+        eprintln!("cannot write checkpoint: {}", err);
+    }
+}
+
+// a settings header, then size \t count \t mtime (secs.nanos or -) \t path
+fn read_checkpoint(file: &String, settings: &str) -> HashMap<PathBuf, Sizes> {
+    let data = match std::fs::read_to_string(file) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("cannot read checkpoint {}: {}", file, err);
+            std::process::exit(1);
+        }
+    };
+    let header = data.lines().next().unwrap_or_default();
+    if header != settings {
+        eprintln!(
+            "checkpoint {} was written with different options, can't resume it ('{}' vs '{}')",
+            file, header, settings
+        );
+        std::process::exit(1);
+    }
+
+    let parse = |line: &str| -> Option<(PathBuf, Sizes)> {
+        let mut parts = line.splitn(4, '\t');
+        let size = parts.next()?.parse().ok()?;
+        let count = parts.next()?.parse().ok()?;
+        let mtime = match parts.next()? {
+            "-" => None,
+            ts => {
+                let mut ts = ts.splitn(2, '.');
+                let secs = ts.next()?.parse().ok()?;
+                let nanos = ts.next()?.parse().ok()?;
+                Some(SystemTime::UNIX_EPOCH + Duration::new(secs, nanos))
+            }
+        };
+        Some((PathBuf::from(parts.next()?), (size, count, mtime)))
+    };
+
+    // a partially written last line just means that path gets scanned again
+    data.lines().filter_map(parse).collect()
+}
+
+fn open_checkpoint(file: &str, append: bool) -> std::fs::File {
+    let res = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(file);
+    match res {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("cannot open checkpoint {}: {}", file, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn write_checkpoint(file: &mut std::fs::File, path: &Path, (size, count, mtime): Sizes) {
+    use std::io::Write;
+    let mtime = match mtime.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok()) {
+        Some(d) => format!("{}.{:09}", d.as_secs(), d.subsec_nanos()),
+        None => "-".into(),
+    };
+    let line = format!("{}\t{}\t{}\t{}\n", size, count, mtime, path.display());
+    if let Err(err) = file.write_all(line.as_bytes()) {
+        eprintln!("cannot write checkpoint: {}", err);
+    }
+}
+
+fn get_sizes(path: &Path, opts: &Opts, state: &mut WalkState) -> Sizes {
+    let (seen, extensions, ages) = (&mut state.seen, &mut state.extensions, &mut state.ages);
+    let (followed, pseudo, dates) = (&mut state.followed, &state.pseudo, &mut state.dates);
+    let (truncated, visited) = (&mut state.truncated, &mut state.visited);
+    *truncated = false;
+    // entries seen so far in the directory at each depth leading to the current entry
+    let mut per_dir: Vec<usize> = vec![];
+    // the mount points are absolute and normalized, so the walked paths have to be too
+    let root = path
+        .canonicalize()
+        .unwrap_or_else(|_| env::current_dir().unwrap_or_default().join(path));
+    let now = SystemTime::now();
+    let mut ignores = state
+        .ignored_above
+        .iter()
+        .map(|(dir, patterns)| (0, dir.clone(), patterns.clone()))
+        .collect::<Vec<_>>();
+    // (depth, symlinks followed to get there) for the directories above the current entry
+    let mut links: Vec<(usize, usize)> = vec![];
+    let dedup = opts.dereference && opts.dedup_targets;
+    let loops = opts.dereference && opts.no_symlink_loops;
+    // metadata the filter already needed, handed on so the entry isn't stat-ed twice
+    let fetched = std::cell::RefCell::new(None);
+
+    let mut walker = walkdir::WalkDir::new(path).follow_links(opts.dereference);
+    if opts.deterministic {
+        walker = walker.sort_by(|l, r| l.file_name().cmp(r.file_name()));
+    }
+
+    walker
         .into_iter()
-        .filter_map(|e| {
-            e.ok()
-                .and_then(|e| e.path().symlink_metadata().ok())
-                .map(|d| d.len())
+        .filter_entry(|e| {
+            fetched.borrow_mut().take();
+            if opts.dustignore && is_dustignored(&mut ignores, e, opts.quiet) {
+                return false;
+            }
+
+            if let (Some(limit), true) = (opts.entry_limit, e.depth() > 0) {
+                per_dir.truncate(e.depth() + 1);
+                per_dir.resize(e.depth() + 1, 0);
+                per_dir[e.depth()] += 1;
+                if per_dir[e.depth()] > limit {
+                    if per_dir[e.depth()] == limit + 1 && !opts.quiet {
+                        let parent = e.path().parent().unwrap_or_else(|| e.path());
+                        eprintln!("truncated {} after {} entries", parent.display(), limit);
+                    }
+                    *truncated = true;
+                    return false;
+                }
+            }
+
+            if opts.exclude_pseudo && e.file_type().is_dir() {
+                let rel = e.path().strip_prefix(path).unwrap_or_else(|_| e.path());
+                if pseudo.contains(&root.join(rel)) {
+                    return false;
+                }
+            }
+
+            let follow_limit = opts.follow_limit.filter(|_| opts.dereference);
+
+            if let Some(limit) = follow_limit {
+                while links.last().is_some_and(|(depth, _)| *depth >= e.depth()) {
+                    links.pop();
+                }
+                let nested = links.last().map_or(0, |(_, n)| *n) + e.path_is_symlink() as usize;
+                if nested > limit {
+                    if !opts.quiet {
+                        eprintln!(
+                            "not following {}: more than {} nested symlinks",
+                            e.path().display(),
+                            limit
+                        );
+                    }
+                    return false;
+                }
+
+                if let (true, Ok(target)) = (e.path_is_symlink(), e.path().canonicalize()) {
+                    if !followed.contains(&target) && followed.len() >= limit {
+                        if !opts.quiet {
+                            eprintln!(
+                                "not following {}: more than {} symlink targets",
+                                e.path().display(),
+                                limit
+                            );
+                        }
+                        return false;
+                    }
+                    followed.insert(target);
+                }
+
+                if e.file_type().is_dir() {
+                    links.push((e.depth(), nested));
+                }
+            }
+
+            let is_dir = e.file_type().is_dir();
+            if dedup || loops && is_dir {
+                let id = match stat(e, opts.io_retries) {
+                    Ok(md) => {
+                        let id = file_id(&md);
+                        *fetched.borrow_mut() = Some(md);
+                        id
+                    }
+                    Err(err) => {
+                        if !opts.quiet || opts.fail_fast {
+                            eprintln!("{}", err);
+                        }
+                        if opts.fail_fast {
+                            std::process::exit(1);
+                        }
+                        return false;
+                    }
+                };
+
+                if let (true, true, Some(id)) = (loops, is_dir, id) {
+                    if !visited.insert(id) {
+                        if !opts.quiet {
+                            eprintln!(
+                                "not entering {}: it was already counted through another path",
+                                e.path().display()
+                            );
+                        }
+                        return false;
+                    }
+                }
+                if let (true, Some(id)) = (dedup, id) {
+                    return seen.insert(id);
+                }
+            }
+            true
         })
-        .fold((0, 0), |(sum, count), c| (sum + c, count + 1))
+        .filter_map(|e| match e {
+            Ok(e) => match fetched
+                .borrow_mut()
+                .take()
+                .map_or_else(|| stat(&e, opts.io_retries), Ok)
+            {
+                Ok(md) => Some((e, md)),
+                Err(err) if opts.fail_fast => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+                Err(..) => None,
+            },
+            // partial totals are worse than none here
+            Err(err) if opts.fail_fast => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            Err(err) => {
+                if opts.dereference && !opts.quiet {
+                    if let Some(path) = err.path() {
+                        match err.loop_ancestor() {
+                            Some(..) => eprintln!("skipping symlink loop at {}", path.display()),
+                            None => eprintln!("skipping {}: {}", path.display(), err),
+                        }
+                    }
+                }
+                None
+            }
+        })
+        .fold((0, 0, None), |(sum, count, mtime), (e, md)| {
+            let mtime = match (mtime, md.modified().ok()) {
+                (Some(l), Some(r)) => Some(std::cmp::max(l, r)),
+                (l, r) => l.or(r),
+            };
+            let size = counted_size(&md, opts);
+
+            if opts.ext_summary.is_some() && md.is_file() {
+                let ext = e.path().extension().map_or_else(
+                    || "<none>".into(),
+                    |s| format!(".{}", s.to_string_lossy().to_lowercase()),
+                );
+                let tally = extensions.entry(ext).or_insert((0, 0));
+                *tally = (tally.0 + size, tally.1 + 1);
+            }
+
+            if let (Some(ranges), true) = (&opts.age_ranges, md.is_file()) {
+                let bucket = match md.modified() {
+                    // anything from the future is as new as it gets
+                    Ok(t) => {
+                        let age = now.duration_since(t).unwrap_or_default();
+                        ranges
+                            .iter()
+                            .position(|(_, d)| age < *d)
+                            .unwrap_or(ranges.len())
+                    }
+                    Err(..) => ranges.len() + 1,
+                };
+                let tally = &mut ages[bucket];
+                *tally = (tally.0 + size, tally.1 + 1);
+            }
+
+            if let (Some(rollup), true) = (opts.rollup, md.is_file()) {
+                let key = md.modified().map_or(i64::MAX, |t| rollup.key(t));
+                let tally = dates.entry(key).or_insert((0, 0));
+                *tally = (tally.0 + size, tally.1 + 1);
+            }
+            (sum + size, count + 1, mtime)
+        })
+}
+
+// device nodes and the like don't occupy any data blocks
+fn counted_size(md: &std::fs::Metadata, opts: &Opts) -> u64 {
+    if opts.files_only_size && !md.is_file() || !opts.count_special && is_special(&md.file_type()) {
+        0
+    } else {
+        md.len()
+    }
+}
+
+// what survives renames and symlinks, for telling whether two paths are the same file
+#[cfg(unix)]
+fn file_id(md: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((md.dev(), md.ino()))
+}
+
+// without one, every path counts as its own file
+#[cfg(not(unix))]
+fn file_id(_: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// network filesystems can fail a stat with EINTR/EAGAIN, which is worth another go
+fn stat(e: &walkdir::DirEntry, retries: usize) -> Result<std::fs::Metadata, walkdir::Error> {
+    use std::io::ErrorKind;
+    let mut tries = 0;
+    loop {
+        match e.metadata() {
+            Ok(md) => return Ok(md),
+            Err(err) => match err.io_error().map(|err| err.kind()) {
+                Some(ErrorKind::Interrupted) | Some(ErrorKind::WouldBlock) if tries < retries => {
+                    tries += 1
+                }
+                _ => return Err(err),
+            },
+        }
+    }
+}
+
+#[cfg(unix)]
+fn pseudo_mounts() -> HashSet<PathBuf> {
+    const PSEUDO: &[&str] = &[
+        "autofs",
+        "binfmt_misc",
+        "bpf",
+        "cgroup",
+        "cgroup2",
+        "configfs",
+        "debugfs",
+        "devpts",
+        "devtmpfs",
+        "efivarfs",
+        "fusectl",
+        "hugetlbfs",
+        "mqueue",
+        "nsfs",
+        "proc",
+        "pstore",
+        "rpc_pipefs",
+        "securityfs",
+        "selinuxfs",
+        "sysfs",
+        "tracefs",
+    ];
+
+    // spaces and such are octal escaped, e.g. `\040`
+    fn unescape(s: &str) -> PathBuf {
+        use std::os::unix::ffi::OsStringExt;
+        let (mut out, bytes) = (vec![], s.as_bytes());
+        let mut i = 0;
+        while i < bytes.len() {
+            let octal = bytes.get(i + 1..i + 4).and_then(|o| {
+                std::str::from_utf8(o)
+                    .ok()
+                    .and_then(|o| u8::from_str_radix(o, 8).ok())
+            });
+            match (bytes[i], octal) {
+                (b'\\', Some(b)) => {
+                    out.push(b);
+                    i += 4
+                }
+                (b, _) => {
+                    out.push(b);
+                    i += 1
+                }
+            }
+        }
+        std::ffi::OsString::from_vec(out).into()
+    }
+
+    // not everything has a /proc, so always skip the usual suspects
+    let mut mounts: HashSet<_> = ["/proc", "/sys", "/dev"]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+    if let Ok(data) = std::fs::read_to_string("/proc/self/mounts") {
+        mounts.extend(data.lines().filter_map(|line| {
+            let mut parts = line.split_whitespace().skip(1);
+            let (point, kind) = (parts.next()?, parts.next()?);
+            if PSEUDO.contains(&kind) {
+                Some(unescape(point))
+            } else {
+                None
+            }
+        }))
+    }
+    mounts
+}
+
+#[cfg(not(unix))]
+fn pseudo_mounts() -> HashSet<PathBuf> {
+    HashSet::new()
+}
+
+#[cfg(unix)]
+fn is_special(ft: &std::fs::FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    ft.is_block_device() || ft.is_char_device() || ft.is_fifo() || ft.is_socket()
+}
+
+#[cfg(not(unix))]
+fn is_special(_: &std::fs::FileType) -> bool {
+    false
+}
+
+// (depth below the dir, dir, patterns) is kept for every .dustignore on the way down to the
+// current entry, where 0 is for ones above the root
+fn is_dustignored(
+    ignores: &mut Vec<(usize, PathBuf, Vec<Pattern>)>,
+    e: &walkdir::DirEntry,
+    quiet: bool,
+) -> bool {
+    while ignores.last().is_some_and(|(depth, ..)| *depth > e.depth()) {
+        ignores.pop();
+    }
+
+    let ignored = ignores
+        .iter()
+        .any(|(_, dir, patterns)| dustignore_matches(dir, patterns, e.path()));
+
+    if !ignored && e.file_type().is_dir() {
+        if let Some(patterns) = read_dustignore(e.path(), quiet) {
+            ignores.push((e.depth() + 1, e.path().to_path_buf(), patterns))
+        }
+    }
+    ignored
+}
+
+fn dustignore_matches(dir: &Path, patterns: &[Pattern], path: &Path) -> bool {
+    let rel = path.strip_prefix(dir).unwrap_or(path);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    patterns
+        .iter()
+        .any(|p| p.matches_path(rel) || p.matches(&name))
+}
+
+// a value that doesn't parse is a usage error rather than a panic
+fn number<T: std::str::FromStr>(matches: &Matches, name: &str) -> Option<T> {
+    matches.opt_str(name).map(|s| match s.parse() {
+        Ok(n) => n,
+        Err(..) => {
+            eprintln!("invalid value for --{}: {}", name, s);
+            std::process::exit(1);
+        }
+    })
+}
+
+// `512`, `10K`, `1.5G`, using the same 1024 based units as format_size
+fn parse_size(s: &str) -> Option<u64> {
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let n: f64 = s[..split].trim().parse().ok()?;
+    let order = match s[split..].to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        "P" => 5,
+        "E" => 6,
+        _ => return None,
+    };
+    Some((n * 1024f64.powi(order)) as u64)
+}
+
+// what --age-ranges reads and --relative-time writes, largest first
+const AGE_UNITS: [(&str, u64); 7] = [
+    ("y", 365 * 24 * 60 * 60),
+    ("mo", 30 * 24 * 60 * 60),
+    ("w", 7 * 24 * 60 * 60),
+    ("d", 24 * 60 * 60),
+    ("h", 60 * 60),
+    ("min", 60),
+    ("s", 1),
+];
+
+// `90s`, `5min`, `12h`, `7d`, `2w`, `6mo` (30 days) or `1y` (365 days)
+fn parse_age(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let n: u64 = s[..split].parse().ok()?;
+    let (_, secs) = AGE_UNITS.iter().find(|(unit, _)| *unit == &s[split..])?;
+    Some(Duration::from_secs(n.checked_mul(*secs)?))
+}
+
+fn read_dustignore(dir: &Path, quiet: bool) -> Option<Vec<Pattern>> {
+    let file = dir.join(".dustignore");
+    let data = std::fs::read_to_string(&file).ok()?;
+    let patterns = data
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match Pattern::new(line) {
+            Ok(p) => Some(p),
+            Err(err) => {
+                if !quiet {
+                    eprintln!("{}: invalid pattern '{}': {}", file.display(), line, err);
+                }
+                None
+            }
+        })
+        .collect();
+    Some(patterns)
+}
+
+fn unix_secs(t: SystemTime) -> i64 {
+    match t.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    }
 }
 
-fn format_size(n: u64) -> String {
-    const SIZES: [&str; 9] = ["B", "K", "M", "G", "T", "P", "E", "Z", "Y"]; // sure
+// (year, month, day) from days since 1970-01-01, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+fn format_duration(secs: u64) -> String {
+    let (unit, n) = AGE_UNITS
+        .iter()
+        .find(|(_, n)| secs >= *n)
+        .unwrap_or(&AGE_UNITS[AGE_UNITS.len() - 1]);
+    format!("{}{}", secs / n, unit)
+}
+
+const SIZE_UNITS: [&str; 9] = ["B", "K", "M", "G", "T", "P", "E", "Z", "Y"]; // sure
+
+// scales to the largest unit that keeps the size at or above 1, unless one is given
+fn format_size(n: u64, unit: Option<usize>) -> String {
     let mut order = 0;
     let mut size = n as f64;
 
-    while size >= 1024.0 && order + 1 < SIZES.len() {
+    while unit.map_or(size >= 1024.0 && order + 1 < SIZE_UNITS.len(), |unit| {
+        order < unit
+    }) {
         order += 1;
         size /= 1024.0
     }
 
-    format!("{:.2} {}", size, SIZES[order])
+    format!("{:.2} {}", size, SIZE_UNITS[order])
 }
 
 fn format_count(n: u64) -> String {
@@ -176,3 +2297,275 @@ fn format_count(n: u64) -> String {
     comma(n, &mut buf);
     buf
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(args: &[&str]) -> Opts {
+        Opts::parse_from("dust", args.iter().map(|s| s.to_string()))
+    }
+
+    fn entry(path: &str, mtime: Option<u64>) -> Entry {
+        Entry {
+            path: path.into(),
+            size: 0,
+            count: 0,
+            dir: false,
+            mtime: mtime.map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            display: path.into(),
+            truncated: false,
+            rank: 0,
+        }
+    }
+
+    fn sorted(args: &[&str]) -> Vec<PathBuf> {
+        let mut entries = vec![
+            entry("d", None),
+            entry("c", Some(20)),
+            entry("b", None),
+            entry("a", Some(20)),
+            entry("e", Some(10)),
+        ];
+        sort_entries(&mut entries, &opts(args));
+        entries.into_iter().map(|e| e.path).collect()
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn mtime_puts_undated_last() {
+        assert_eq!(
+            sorted(&["--sort", "mtime"]),
+            paths(&["e", "a", "c", "b", "d"])
+        );
+        assert_eq!(
+            sorted(&["--sort", "mtime:desc"]),
+            paths(&["a", "c", "e", "b", "d"])
+        );
+    }
+
+    #[test]
+    fn mtime_none_first() {
+        assert_eq!(
+            sorted(&["--sort", "mtime", "--none-first"]),
+            paths(&["b", "d", "e", "a", "c"])
+        );
+    }
+
+    #[test]
+    fn mtime_reverse_flips_everything() {
+        assert_eq!(
+            sorted(&["--sort", "mtime", "-r"]),
+            paths(&["d", "b", "c", "a", "e"])
+        );
+        assert_eq!(
+            sorted(&["--sort", "mtime", "--none-first", "-r"]),
+            paths(&["c", "a", "e", "d", "b"])
+        );
+    }
+
+    #[test]
+    fn mtime_ties_use_the_displayed_path() {
+        let mut entries = vec![entry("x/1", Some(5)), entry("y/0", Some(5))];
+        entries[0].display = "b".into();
+        entries[1].display = "a".into();
+        sort_entries(&mut entries, &opts(&["--sort", "mtime"]));
+        assert_eq!(entries[0].path, PathBuf::from("y/0"));
+    }
+
+    fn at(secs: i64) -> SystemTime {
+        match secs {
+            secs if secs < 0 => SystemTime::UNIX_EPOCH - Duration::from_secs(-secs as u64),
+            secs => SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64),
+        }
+    }
+
+    #[test]
+    fn braces() {
+        assert_eq!(
+            expand_braces("{src,tests}/*.rs"),
+            ["src/*.rs", "tests/*.rs"]
+        );
+        assert_eq!(expand_braces("x{a,{b,c}}"), ["xa", "xb", "xc"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand_braces("{a,}"), ["a", ""]);
+        assert_eq!(expand_braces("no braces"), ["no braces"]);
+    }
+
+    #[test]
+    fn braces_left_alone() {
+        assert_eq!(expand_braces("{a}"), ["{a}"]);
+        assert_eq!(expand_braces("{a,b"), ["{a,b"]);
+        assert_eq!(expand_braces("a}b"), ["a}b"]);
+        assert_eq!(expand_braces("{a}{b,c}"), ["{a}b", "{a}c"]);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("10K"), Some(10 * 1024));
+        assert_eq!(parse_size("1.5G"), Some(3 * 512 * 1024 * 1024));
+        assert_eq!(parse_size("2m"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("4 B"), Some(4));
+        assert_eq!(parse_size("10X"), None);
+        assert_eq!(parse_size("K"), None);
+        assert_eq!(parse_size(""), None);
+    }
+
+    #[test]
+    fn ages() {
+        let days = |n: u64| Some(Duration::from_secs(n * 24 * 60 * 60));
+        assert_eq!(parse_age("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_age("12h"), Some(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(parse_age("7d"), days(7));
+        assert_eq!(parse_age("2w"), days(14));
+        assert_eq!(parse_age("5min"), Some(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_age("6mo"), days(180));
+        assert_eq!(parse_age("6m"), None);
+        assert_eq!(parse_age("1y"), days(365));
+        assert_eq!(parse_age("5"), None);
+        assert_eq!(parse_age("3x"), None);
+        assert_eq!(parse_age("d"), None);
+        assert_eq!(parse_age("999999999999999y"), None);
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_723), (2024, 1, 1));
+    }
+
+    #[test]
+    fn rollup_keys() {
+        let day = 24 * 60 * 60;
+        // wednesday 2024-01-03, in the week of monday 2024-01-01
+        let t = at(19_725 * day + 12 * 60 * 60);
+        assert_eq!(Rollup::Day.key(t), 19_725);
+        assert_eq!(Rollup::Week.key(t), 19_723);
+        assert_eq!(Rollup::Month.key(t), 2024 * 12);
+        assert_eq!(Rollup::Month.label(Rollup::Month.key(t)), "2024-01");
+
+        // wednesday 1969-12-31, in the week of monday 1969-12-29
+        let t = at(-1);
+        assert_eq!(Rollup::Day.key(t), -1);
+        assert_eq!(Rollup::Week.key(t), -3);
+        assert_eq!(Rollup::Week.label(-3), "1969-12-29");
+        assert_eq!(Rollup::Month.key(t), 1969 * 12 + 11);
+    }
+
+    #[test]
+    fn templates() {
+        let lookup = |name: &str| match name {
+            "total_size" => Some("1.00 K".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_template("{total_size} in {{braces}}", lookup),
+            Ok("1.00 K in {braces}".to_string())
+        );
+        assert_eq!(
+            expand_template("{nope}", lookup),
+            Err("unknown placeholder: {nope}".to_string())
+        );
+        assert_eq!(
+            expand_template("{total_size", lookup),
+            Err("unclosed placeholder: {total_size".to_string())
+        );
+    }
+
+    #[test]
+    fn names_grow_until_unique() {
+        let mut entries = vec![entry("a/x", None), entry("b/x", None), entry("c/y", None)];
+        display_names(&mut entries, true);
+        let shown = entries
+            .iter()
+            .map(|e| e.display.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(shown, paths(&["a/x", "b/x", "y"]));
+    }
+
+    #[test]
+    fn names_that_stay_the_same() {
+        let mut entries = vec![entry("a/b/x", None), entry("c/b/x", None), entry("x", None)];
+        display_names(&mut entries, true);
+        let shown = entries
+            .iter()
+            .map(|e| e.display.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(shown, paths(&["a/b/x", "c/b/x", "x"]));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(59), "59s");
+        assert_eq!(format_duration(5 * 60), "5min");
+        assert_eq!(format_duration(2 * 60 * 60 + 59), "2h");
+        assert_eq!(format_duration(40 * 24 * 60 * 60), "1mo");
+        assert_eq!(format_duration(400 * 24 * 60 * 60), "1y");
+    }
+
+    #[test]
+    fn times() {
+        let fmt = opts(&[]).format;
+        assert_eq!(fmt.time(at(0)), "1970-01-01 00:00");
+        assert_eq!(
+            fmt.time(at(19_723 * 24 * 60 * 60 + 90 * 60)),
+            "2024-01-01 01:30"
+        );
+
+        let fmt = opts(&["--relative-time"]).format;
+        let soon = SystemTime::now() + Duration::from_secs(2 * 60 * 60 + 30);
+        assert_eq!(fmt.time(soon), "in 2h");
+        let ago = SystemTime::now() - Duration::from_secs(3 * 24 * 60 * 60 + 30);
+        assert_eq!(fmt.time(ago), "3d");
+    }
+
+    #[test]
+    fn sizes_in_a_unit() {
+        assert_eq!(format_size(0, None), "0.00 B");
+        assert_eq!(format_size(1536, None), "1.50 K");
+        assert_eq!(format_size(1536, Some(0)), "1536.00 B");
+        assert_eq!(format_size(512, Some(1)), "0.50 K");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024, Some(2)), "3072.00 M");
+    }
+
+    #[test]
+    fn sort_keys() {
+        assert_eq!(
+            SortKey::parse("size"),
+            Some((SortKey::Size, Direction::Asc))
+        );
+        assert_eq!(
+            SortKey::parse("path:asc"),
+            Some((SortKey::Path, Direction::Asc))
+        );
+        assert_eq!(
+            SortKey::parse("mtime:desc"),
+            Some((SortKey::Mtime, Direction::Desc))
+        );
+        assert_eq!(SortKey::parse("count:up"), None);
+        assert_eq!(SortKey::parse("size:"), None);
+        assert_eq!(SortKey::parse(":desc"), None);
+        assert_eq!(SortKey::parse("name"), None);
+    }
+
+    #[test]
+    fn common_prefixes() {
+        let prefix = |ps: &[&str]| {
+            let entries = ps.iter().map(|p| entry(p, None)).collect::<Vec<_>>();
+            common_prefix(&entries)
+        };
+        assert_eq!(prefix(&["a/b/c", "a/b/d/e", "a/x"]), PathBuf::from("a"));
+        assert_eq!(prefix(&["/t/a", "/t/b/"]), PathBuf::from("/t"));
+        assert_eq!(prefix(&["a/b"]), PathBuf::from("a"));
+        assert_eq!(prefix(&["x", "y"]), PathBuf::new());
+        assert_eq!(prefix(&["ab/c", "a/c"]), PathBuf::new());
+        assert_eq!(prefix(&[]), PathBuf::new());
+    }
+}